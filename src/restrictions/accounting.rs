@@ -0,0 +1,109 @@
+use crate::restrictions::types::ConnectionLimits;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Tracks how many tunnels are currently open per restriction rule name, so a rule's
+/// `max_connections` cap can be enforced wherever a tunnel is admitted. Cloning is cheap;
+/// every clone shares the same counters.
+#[derive(Clone, Default)]
+pub struct RuleAccounting(Arc<Mutex<HashMap<String, usize>>>);
+
+impl RuleAccounting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves one connection slot for `rule_name` if `limits.max_connections` allows it.
+    /// Returns `None` once the rule is already at capacity. The returned guard releases the
+    /// slot when the tunnel closes, so callers should hold it for the tunnel's lifetime.
+    pub fn try_admit(&self, rule_name: &str, limits: ConnectionLimits) -> Option<ConnectionGuard> {
+        let Some(max) = limits.max_connections else {
+            return Some(ConnectionGuard { accounting: None, rule_name: rule_name.to_string() });
+        };
+
+        let mut open = self.0.lock().unwrap();
+        let count = open.entry(rule_name.to_string()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard { accounting: Some(self.clone()), rule_name: rule_name.to_string() })
+    }
+
+    fn release(&self, rule_name: &str) {
+        let mut open = self.0.lock().unwrap();
+        if let Some(count) = open.get_mut(rule_name) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    #[cfg(test)]
+    fn open_connections(&self, rule_name: &str) -> usize {
+        self.0.lock().unwrap().get(rule_name).copied().unwrap_or(0)
+    }
+}
+
+/// Held for the lifetime of an admitted tunnel; releases its rule's connection slot on
+/// drop. A rule with no `max_connections` cap produces a guard that releases nothing.
+pub struct ConnectionGuard {
+    accounting: Option<RuleAccounting>,
+    rule_name: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(accounting) = &self.accounting {
+            accounting.release(&self.rule_name);
+        }
+    }
+}
+
+/// Returns true once `last_activity` is older than the rule's `idle_timeout`, so the
+/// session layer's reaper knows to close the tunnel. Rules without an `idle_timeout` are
+/// never reaped.
+pub fn is_idle(limits: ConnectionLimits, last_activity: Instant) -> bool {
+    limits.idle_timeout.map(|timeout| last_activity.elapsed() > timeout).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_up_to_max_connections_then_rejects() {
+        let accounting = RuleAccounting::new();
+        let limits = ConnectionLimits { max_connections: Some(2), idle_timeout: None };
+
+        let first = accounting.try_admit("web", limits).expect("first connection admitted");
+        let second = accounting.try_admit("web", limits).expect("second connection admitted");
+        assert_eq!(accounting.open_connections("web"), 2);
+        assert!(accounting.try_admit("web", limits).is_none(), "third connection should be rejected at the cap");
+
+        drop(first);
+        assert_eq!(accounting.open_connections("web"), 1);
+        let third = accounting.try_admit("web", limits).expect("slot freed after drop");
+
+        drop(second);
+        drop(third);
+        assert_eq!(accounting.open_connections("web"), 0);
+    }
+
+    #[test]
+    fn unlimited_rule_is_always_admitted() {
+        let accounting = RuleAccounting::new();
+        let limits = ConnectionLimits::default();
+        for _ in 0..100 {
+            assert!(accounting.try_admit("unlimited", limits).is_some());
+        }
+        assert_eq!(accounting.open_connections("unlimited"), 0);
+    }
+
+    #[test]
+    fn idle_timeout_reaping() {
+        let limits = ConnectionLimits { max_connections: None, idle_timeout: Some(std::time::Duration::from_secs(3600)) };
+        assert!(!is_idle(limits, Instant::now()));
+        assert!(is_idle(limits, Instant::now() - std::time::Duration::from_secs(3601)));
+        assert!(!is_idle(ConnectionLimits::default(), Instant::now() - std::time::Duration::from_secs(u64::MAX / 2)));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod accounting;
+pub mod types;
+pub mod watch;
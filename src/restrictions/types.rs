@@ -2,19 +2,131 @@ use crate::LocalProtocol;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RestrictionsRules {
     pub restrictions: Vec<RestrictionConfig>,
 }
 
+impl RestrictionsRules {
+    /// Evaluates the rules top-to-bottom and returns the action of the first rule whose
+    /// match criteria (and, for an `Allow` rule, whose tunnel spec and authentication
+    /// requirement) are satisfied by `request`. Defaults to `RestrictionAction::Deny` when
+    /// no rule matches, so an empty or exhausted rule set fails closed.
+    pub fn evaluate(&self, request: &RestrictionRequest) -> RestrictionAction {
+        self.restrictions
+            .iter()
+            .find_map(|restriction| restriction.resolve(request))
+            .map(|resolution| resolution.action)
+            .unwrap_or(RestrictionAction::Deny)
+    }
+
+    /// Like `evaluate`, but also returns the name of the winning rule and the
+    /// `ConnectionLimits` of the `allow` clause that admitted the request, for the session
+    /// accounting layer to enforce wherever a tunnel is actually admitted. `None` alongside
+    /// `RestrictionAction::Allow` means the winning rule had no `allow` clauses to size a
+    /// limit from (unlimited).
+    pub fn evaluate_with_limits(&self, request: &RestrictionRequest) -> (RestrictionAction, Option<(String, ConnectionLimits)>) {
+        match self.restrictions.iter().find_map(|restriction| restriction.resolve(request).map(|resolution| (restriction, resolution))) {
+            Some((restriction, resolution)) => {
+                let limits = resolution.allow.map(|allow| (restriction.name.clone(), allow.limits()));
+                (resolution.action, limits)
+            }
+            None => (RestrictionAction::Deny, None),
+        }
+    }
+}
+
+/// The outcome of evaluating one `RestrictionConfig` against a request: the action to take,
+/// and, for an `Allow` rule with `allow` clauses, the specific clause that admitted it.
+struct Resolution<'a> {
+    action: RestrictionAction,
+    allow: Option<&'a AllowConfig>,
+}
+
+impl RestrictionConfig {
+    /// Returns `Some` when this rule fires for `request`: its `match` criteria are
+    /// satisfied, and — for an `Allow` rule with `allow` clauses — at least one clause
+    /// admits the caller's tunnel spec and identity. A `Deny`/`Reject` rule, or an `Allow`
+    /// rule with no `allow` clauses, fires on `match` alone.
+    fn resolve(&self, request: &RestrictionRequest) -> Option<Resolution<'_>> {
+        if !self.r#match.iter().any(|m| m.matches(request)) {
+            return None;
+        }
+        if self.action != RestrictionAction::Allow || self.allow.is_empty() {
+            return Some(Resolution { action: self.action.clone(), allow: None });
+        }
+        self.allow
+            .iter()
+            .find(|allow| allow.admits(request.identity, request.tunnel))
+            .map(|allow| Resolution { action: self.action.clone(), allow: Some(allow) })
+    }
+}
+
+/// The pieces of an incoming websocket upgrade request that `MatchConfig` and `AllowConfig`
+/// variants are evaluated against.
+#[derive(Debug, Clone)]
+pub struct RestrictionRequest<'a> {
+    pub path: &'a str,
+    pub source_addr: IpAddr,
+    pub sni: Option<&'a str>,
+    pub headers: &'a [(String, String)],
+    pub identity: &'a AuthenticatedIdentity,
+    pub tunnel: &'a RequestedTunnel,
+}
+
+/// The protocol, port and destination of the tunnel a caller is requesting, used to
+/// evaluate an `AllowConfig` clause's `protocol`/`port`/`cidr`/`host` criteria.
+#[derive(Debug, Clone)]
+pub struct RequestedTunnel {
+    pub protocol: RequestedProtocol,
+    pub port: u16,
+    pub destination_addr: IpAddr,
+    pub destination_host: String,
+}
+
+/// Which kind of `AllowConfig` clause a `RequestedTunnel` can be admitted by.
+#[derive(Debug, Clone)]
+pub enum RequestedProtocol {
+    Tunnel(TunnelConfigProtocol),
+    ReverseTunnel(ReverseTunnelConfigProtocol),
+}
+
+/// Credentials presented on the upgrade request, extracted from the `Authorization` header
+/// or the client's TLS certificate. Absent fields simply fail any `AllowAuthConfig` that
+/// requires them.
+#[derive(Debug, Clone, Default)]
+pub struct AuthenticatedIdentity {
+    pub bearer_token: Option<MaskedString>,
+    pub client_cert_cn: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RestrictionConfig {
     pub name: String,
     #[serde(deserialize_with = "deserialize_non_empty_vec")]
     pub r#match: Vec<MatchConfig>,
+    #[serde(default)]
     pub allow: Vec<AllowConfig>,
+    #[serde(default)]
+    pub action: RestrictionAction,
+}
+
+/// What to do with a connection once a rule's `match` criteria are satisfied.
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+pub enum RestrictionAction {
+    #[default]
+    Allow,
+    /// Silently drop the connection, as if the server never received it.
+    Deny,
+    /// Refuse the connection with an explicit reason (e.g. HTTP 403, or a close frame
+    /// carrying the reason), instead of just dropping it.
+    Reject,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -22,6 +134,29 @@ pub enum MatchConfig {
     Any,
     #[serde(with = "serde_regex")]
     PathPrefix(Regex),
+    SourceIp(Vec<IpNet>),
+    #[serde(with = "serde_regex")]
+    Sni(Regex),
+    HttpHeader {
+        name: String,
+        #[serde(with = "serde_regex")]
+        value: Regex,
+    },
+}
+
+impl MatchConfig {
+    pub fn matches(&self, request: &RestrictionRequest) -> bool {
+        match self {
+            MatchConfig::Any => true,
+            MatchConfig::PathPrefix(prefix) => prefix.is_match(request.path),
+            MatchConfig::SourceIp(nets) => nets.iter().any(|net| net.contains(&request.source_addr)),
+            MatchConfig::Sni(regex) => request.sni.map(|sni| regex.is_match(sni)).unwrap_or(false),
+            MatchConfig::HttpHeader { name, value } => request
+                .headers
+                .iter()
+                .any(|(header_name, header_value)| header_name.eq_ignore_ascii_case(name) && value.is_match(header_value)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -30,6 +165,49 @@ pub enum AllowConfig {
     Tunnel(AllowTunnelConfig),
 }
 
+impl AllowConfig {
+    /// Whether this clause admits `tunnel`, i.e. its `protocol`/`port`/`cidr`/`host`
+    /// criteria all match the request and (if set) its `auth` requirement is satisfied by
+    /// `identity`. A clause never admits a request of the other kind (e.g. a
+    /// `Tunnel` clause never admits a `RequestedProtocol::ReverseTunnel`).
+    fn admits(&self, identity: &AuthenticatedIdentity, tunnel: &RequestedTunnel) -> bool {
+        match (self, &tunnel.protocol) {
+            (AllowConfig::Tunnel(cfg), RequestedProtocol::Tunnel(protocol)) => {
+                (cfg.protocol.is_empty() || cfg.protocol.contains(protocol))
+                    && (cfg.port.is_empty() || cfg.port.iter().any(|range| range.contains(&tunnel.port)))
+                    && cfg.cidr.iter().any(|net| net.contains(&tunnel.destination_addr))
+                    && cfg.host.is_match(&tunnel.destination_host)
+                    && cfg.auth.as_ref().map(|auth| auth.is_satisfied_by(identity)).unwrap_or(true)
+            }
+            (AllowConfig::ReverseTunnel(cfg), RequestedProtocol::ReverseTunnel(protocol)) => {
+                (cfg.protocol.is_empty() || cfg.protocol.contains(protocol))
+                    && (cfg.port.is_empty() || cfg.port.iter().any(|range| range.contains(&tunnel.port)))
+                    && cfg.cidr.iter().any(|net| net.contains(&tunnel.destination_addr))
+                    && cfg.auth.as_ref().map(|auth| auth.is_satisfied_by(identity)).unwrap_or(true)
+            }
+            _ => false,
+        }
+    }
+
+    /// Caps the session accounting layer should enforce for a tunnel admitted by this
+    /// clause.
+    pub fn limits(&self) -> ConnectionLimits {
+        match self {
+            AllowConfig::ReverseTunnel(cfg) => cfg.into(),
+            AllowConfig::Tunnel(cfg) => cfg.into(),
+        }
+    }
+}
+
+/// Per-rule caps enforced by the session accounting layer wherever a tunnel is admitted:
+/// how many concurrent tunnels a matching rule may hold open, and how long one may sit idle
+/// before being reaped. `None` means unlimited / never reaped.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AllowTunnelConfig {
     #[serde(default)]
@@ -45,6 +223,22 @@ pub struct AllowTunnelConfig {
 
     #[serde(default = "default_cidr")]
     pub cidr: Vec<IpNet>,
+
+    #[serde(default)]
+    pub auth: Option<AllowAuthConfig>,
+
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration_secs_opt")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl From<&AllowTunnelConfig> for ConnectionLimits {
+    fn from(cfg: &AllowTunnelConfig) -> Self {
+        ConnectionLimits { max_connections: cfg.max_connections, idle_timeout: cfg.idle_timeout }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -58,22 +252,109 @@ pub struct AllowReverseTunnelConfig {
 
     #[serde(default = "default_cidr")]
     pub cidr: Vec<IpNet>,
+
+    #[serde(default)]
+    pub auth: Option<AllowAuthConfig>,
+
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_duration_secs_opt")]
+    pub idle_timeout: Option<Duration>,
 }
 
+impl From<&AllowReverseTunnelConfig> for ConnectionLimits {
+    fn from(cfg: &AllowReverseTunnelConfig) -> Self {
+        ConnectionLimits { max_connections: cfg.max_connections, idle_timeout: cfg.idle_timeout }
+    }
+}
+
+/// The identity a caller must authenticate as for the enclosing `AllowConfig` clause to
+/// admit it.
+#[derive(Debug, Clone, Deserialize)]
+pub enum AllowAuthConfig {
+    BearerToken(Vec<MaskedString>),
+    #[serde(with = "serde_regex")]
+    ClientCertCn(Regex),
+}
+
+impl AllowAuthConfig {
+    pub fn is_satisfied_by(&self, identity: &AuthenticatedIdentity) -> bool {
+        match self {
+            AllowAuthConfig::BearerToken(tokens) => identity
+                .bearer_token
+                .as_ref()
+                .map(|presented| tokens.iter().any(|token| token.constant_time_eq(presented)))
+                .unwrap_or(false),
+            AllowAuthConfig::ClientCertCn(regex) => identity.client_cert_cn.as_deref().map(|cn| regex.is_match(cn)).unwrap_or(false),
+        }
+    }
+}
+
+/// A string that must never be printed verbatim, such as a bearer token. `Debug` and
+/// `Display` always render `MASKED`, so a stray `{:?}` in a log statement or error message
+/// can't leak it.
+#[derive(Clone, Deserialize, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct MaskedString(String);
+
+impl MaskedString {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Compares two masked strings without branching on where they first differ, so an
+    /// attacker who can measure comparison latency can't use it to recover a bearer token
+    /// byte by byte. Only the (non-secret) length is compared the fast way.
+    pub fn constant_time_eq(&self, other: &MaskedString) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.0.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
+
+impl std::fmt::Debug for MaskedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MASKED")
+    }
+}
+
+impl std::fmt::Display for MaskedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MASKED")
+    }
+}
+
+/// Protocols a tunnel rule can match on. `#[non_exhaustive]` because `LocalProtocol` can
+/// grow variants that this enum has no reason to expose yet; adding one here is not a
+/// breaking change for downstream matches.
 #[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum TunnelConfigProtocol {
+    #[serde(alias = "Tcp")]
     Tcp,
+    #[serde(alias = "Udp")]
     Udp,
-    Unknown,
 }
 
+/// Protocols a reverse tunnel rule can match on. See `TunnelConfigProtocol` for why this is
+/// `#[non_exhaustive]`.
 #[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum ReverseTunnelConfigProtocol {
+    #[serde(alias = "Tcp")]
     Tcp,
+    #[serde(alias = "Udp")]
     Udp,
+    #[serde(alias = "Socks5")]
     Socks5,
+    #[serde(alias = "Unix")]
     Unix,
-    Unknown,
 }
 
 pub fn default_host() -> Regex {
@@ -110,6 +391,14 @@ where
     Ok(ranges)
 }
 
+fn deserialize_duration_secs_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = Option::<u64>::deserialize(deserializer)?;
+    Ok(secs.map(Duration::from_secs))
+}
+
 fn deserialize_non_empty_vec<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
 where
     D: Deserializer<'de>,
@@ -123,26 +412,228 @@ where
     }
 }
 
-impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
-    fn from(value: &LocalProtocol) -> Self {
+/// Top-level keys recognized in the compact `--restrict-to` syntax, e.g.
+/// `name=web,match=path-prefix:/api,allow=tunnel:protocol=tcp;port=443;cidr=10.0.0.0/8`.
+const RESTRICTION_CONFIG_KEYS: [&str; 4] = ["name", "match", "allow", "action"];
+
+/// Splits a `match=`/`allow=` field into its `|`-separated entries. `|` is also the regex
+/// alternation metacharacter, so a literal `|` inside an entry's regex (e.g.
+/// `sni:(foo|bar)\.corp$`) must be escaped as `\|` to keep it out of the split; escaped
+/// pipes are unescaped back to `|` once the entry boundary has been located.
+fn split_unescaped_pipe(s: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'|') {
+            current.push('|');
+            chars.next();
+        } else if c == '|' {
+            entries.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    entries.push(current);
+    entries
+}
+
+/// Splits a compact restriction rule into its top-level `key=value` fields. A comma only
+/// starts a new field when it is immediately followed by one of `RESTRICTION_CONFIG_KEYS`,
+/// which lets `allow=...` itself contain commas (e.g. a port list or a CIDR list). A value
+/// that happens to contain a literal `,<key>=` (e.g. an `http-header` regex matching
+/// `,action=`) would otherwise be mis-split into a spurious field; escape that comma as
+/// `\,` to keep it part of the value. Escaped commas are unescaped back to `,` once the
+/// field boundary has been located.
+fn tokenize_restriction_config(s: &str) -> HashMap<&str, String> {
+    let mut starts: Vec<(usize, &str)> = Vec::new();
+    for key in RESTRICTION_CONFIG_KEYS {
+        let needle = format!("{key}=");
+        let mut search_from = 0;
+        while let Some(idx) = s[search_from..].find(needle.as_str()) {
+            let abs = search_from + idx;
+            let preceded_by_comma = abs > 0 && s.as_bytes()[abs - 1] == b',';
+            let comma_is_escaped = abs >= 2 && s.as_bytes()[abs - 2] == b'\\';
+            if (abs == 0 || preceded_by_comma) && !comma_is_escaped {
+                starts.push((abs, key));
+            }
+            search_from = abs + needle.len();
+        }
+    }
+    starts.sort_by_key(|(idx, _)| *idx);
+
+    let mut fields = HashMap::new();
+    for (i, (start, key)) in starts.iter().enumerate() {
+        let value_start = start + key.len() + 1;
+        let value_end = starts.get(i + 1).map(|(next_start, _)| next_start - 1).unwrap_or(s.len());
+        fields.insert(*key, s[value_start..value_end].replace("\\,", ","));
+    }
+    fields
+}
+
+impl FromStr for RestrictionConfig {
+    type Err = anyhow::Error;
+
+    /// Parses the compact single-line syntax accepted by `--restrict-to`, e.g.
+    /// `name=web,match=path-prefix:/api,allow=tunnel:protocol=tcp;port=443,8000..9000;cidr=10.0.0.0/8;host=.*\.corp$`
+    ///
+    /// The string is first tokenized into its top-level fields. Each `match`/`allow` entry is
+    /// then turned into the equivalent JSON representation and run through the same serde
+    /// `Deserialize` impls used for the YAML/JSON restrictions file, so validation (port
+    /// ranges, default CIDRs, ...) stays in one place. `name` is taken as is, and `action`
+    /// is parsed by `RestrictionAction::from_str` (lowercase `allow`/`deny`/`reject`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = tokenize_restriction_config(s);
+
+        let name = fields
+            .get("name")
+            .ok_or_else(|| anyhow::anyhow!("restriction rule `{s}` is missing the mandatory `name` field"))?
+            .to_string();
+
+        let r#match = split_unescaped_pipe(
+            fields.get("match").ok_or_else(|| anyhow::anyhow!("restriction rule `{name}` is missing the mandatory `match` field"))?,
+        )
+        .iter()
+        .map(|s| MatchConfig::from_str(s))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+        if r#match.is_empty() {
+            anyhow::bail!("restriction rule `{name}` must have at least one `match` entry");
+        }
+
+        let allow = fields
+            .get("allow")
+            .map(|s| split_unescaped_pipe(s).iter().map(|s| AllowConfig::from_str(s)).collect::<anyhow::Result<Vec<_>>>())
+            .transpose()?
+            .unwrap_or_default();
+
+        let action = fields.get("action").map(|s| RestrictionAction::from_str(s)).transpose()?.unwrap_or_default();
+
+        Ok(RestrictionConfig { name, r#match, allow, action })
+    }
+}
+
+impl FromStr for RestrictionAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(RestrictionAction::Allow),
+            "deny" => Ok(RestrictionAction::Deny),
+            "reject" => Ok(RestrictionAction::Reject),
+            other => anyhow::bail!("unknown restriction action `{other}`, expected `allow`, `deny` or `reject`"),
+        }
+    }
+}
+
+impl FromStr for MatchConfig {
+    type Err = anyhow::Error;
+
+    /// Parses one `|`-separated entry of a `match=` field, e.g. `path-prefix:/api`,
+    /// `source-ip:10.0.0.0/8,192.168.0.0/16`, `sni:.*\.corp$` or `http-header:x-api-key=.+`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "any" {
+            return Ok(MatchConfig::Any);
+        }
+        let (kind, value) = s.split_once(':').ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid match expression `{s}`, expected `any`, `path-prefix:<regex>`, `source-ip:<cidr>[,<cidr>...]`, \
+                 `sni:<regex>` or `http-header:<name>=<regex>`"
+            )
+        })?;
+        let json = match kind {
+            "path-prefix" => serde_json::json!({ "PathPrefix": value }),
+            "source-ip" => {
+                let nets: Vec<&str> = value.split(',').map(str::trim).collect();
+                serde_json::json!({ "SourceIp": nets })
+            }
+            "sni" => serde_json::json!({ "Sni": value }),
+            "http-header" => {
+                let (name, regex) = value
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("invalid `http-header` match `{value}`, expected `<name>=<regex>`"))?;
+                serde_json::json!({ "HttpHeader": { "name": name, "value": regex } })
+            }
+            other => anyhow::bail!("unknown match kind `{other}`"),
+        };
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+impl FromStr for AllowConfig {
+    type Err = anyhow::Error;
+
+    /// Parses one `|`-separated entry of an `allow=` field, e.g.
+    /// `tunnel:protocol=tcp;port=443,8000..9000;cidr=10.0.0.0/8;host=.*\.corp$` or
+    /// `reverse-tunnel:protocol=tcp;port=1080`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid allow expression `{s}`, expected `tunnel:...` or `reverse-tunnel:...`"))?;
+
+        let mut fields = HashMap::new();
+        for part in rest.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid allow field `{part}`, expected `key=value`"))?;
+            fields.insert(key, value);
+        }
+
+        let protocol: Vec<&str> = fields.get("protocol").map(|v| v.split(',').map(str::trim).collect()).unwrap_or_default();
+        let port: Vec<&str> = fields.get("port").map(|v| v.split(',').map(str::trim).collect()).unwrap_or_default();
+        let cidr: Option<Vec<&str>> = fields.get("cidr").map(|v| v.split(',').map(str::trim).collect());
+
+        let mut obj = serde_json::json!({ "protocol": protocol, "port": port });
+        if let Some(cidr) = cidr {
+            obj["cidr"] = serde_json::json!(cidr);
+        }
+
+        let json = match kind {
+            "tunnel" => {
+                if let Some(host) = fields.get("host") {
+                    obj["host"] = serde_json::json!(host);
+                }
+                serde_json::json!({ "Tunnel": obj })
+            }
+            "reverse-tunnel" => serde_json::json!({ "ReverseTunnel": obj }),
+            other => anyhow::bail!("unknown allow kind `{other}`"),
+        };
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+/// A `LocalProtocol` that has no equivalent `TunnelConfigProtocol` / `ReverseTunnelConfigProtocol`
+/// variant, so it can never be matched by a restriction rule of that kind.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{0:?} is not a {1} protocol")]
+pub struct UnsupportedProtocol(LocalProtocol, &'static str);
+
+impl TryFrom<&LocalProtocol> for ReverseTunnelConfigProtocol {
+    type Error = UnsupportedProtocol;
+
+    fn try_from(value: &LocalProtocol) -> Result<Self, Self::Error> {
         match value {
+            LocalProtocol::ReverseTcp => Ok(ReverseTunnelConfigProtocol::Tcp),
+            LocalProtocol::ReverseUdp { .. } => Ok(ReverseTunnelConfigProtocol::Udp),
+            LocalProtocol::ReverseSocks5 => Ok(ReverseTunnelConfigProtocol::Socks5),
+            LocalProtocol::ReverseUnix { .. } => Ok(ReverseTunnelConfigProtocol::Unix),
             LocalProtocol::Tcp { .. }
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Stdio
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::TProxyTcp { .. }
             | LocalProtocol::TProxyUdp { .. }
-            | LocalProtocol::Unix { .. } => ReverseTunnelConfigProtocol::Unknown,
-            LocalProtocol::ReverseTcp => ReverseTunnelConfigProtocol::Tcp,
-            LocalProtocol::ReverseUdp { .. } => ReverseTunnelConfigProtocol::Udp,
-            LocalProtocol::ReverseSocks5 => ReverseTunnelConfigProtocol::Socks5,
-            LocalProtocol::ReverseUnix { .. } => ReverseTunnelConfigProtocol::Unix,
+            | LocalProtocol::Unix { .. } => Err(UnsupportedProtocol(value.clone(), "reverse tunnel")),
         }
     }
 }
-impl From<&LocalProtocol> for TunnelConfigProtocol {
-    fn from(value: &LocalProtocol) -> Self {
+
+impl TryFrom<&LocalProtocol> for TunnelConfigProtocol {
+    type Error = UnsupportedProtocol;
+
+    fn try_from(value: &LocalProtocol) -> Result<Self, Self::Error> {
         match value {
+            LocalProtocol::Tcp { .. } => Ok(TunnelConfigProtocol::Tcp),
+            LocalProtocol::Udp { .. } => Ok(TunnelConfigProtocol::Udp),
             LocalProtocol::ReverseTcp
             | LocalProtocol::ReverseUdp { .. }
             | LocalProtocol::ReverseSocks5
@@ -151,9 +642,281 @@ impl From<&LocalProtocol> for TunnelConfigProtocol {
             | LocalProtocol::Socks5 { .. }
             | LocalProtocol::TProxyTcp { .. }
             | LocalProtocol::TProxyUdp { .. }
-            | LocalProtocol::Unix { .. } => TunnelConfigProtocol::Unknown,
-            LocalProtocol::Tcp { .. } => TunnelConfigProtocol::Tcp,
-            LocalProtocol::Udp { .. } => TunnelConfigProtocol::Udp,
+            | LocalProtocol::Unix { .. } => Err(UnsupportedProtocol(value.clone(), "tunnel")),
+        }
+    }
+}
+
+impl RequestedTunnel {
+    /// Builds the descriptor for a forward tunnel request, rejecting up front a
+    /// `local_protocol` that has no `TunnelConfigProtocol` equivalent instead of letting it
+    /// silently fail to match any rule.
+    pub fn for_tunnel(
+        local_protocol: &LocalProtocol,
+        port: u16,
+        destination_addr: IpAddr,
+        destination_host: String,
+    ) -> Result<Self, UnsupportedProtocol> {
+        Ok(RequestedTunnel { protocol: RequestedProtocol::Tunnel(TunnelConfigProtocol::try_from(local_protocol)?), port, destination_addr, destination_host })
+    }
+
+    /// Builds the descriptor for a reverse tunnel request. See `for_tunnel` for why
+    /// `local_protocol` is fallible.
+    pub fn for_reverse_tunnel(local_protocol: &LocalProtocol, port: u16, destination_addr: IpAddr) -> Result<Self, UnsupportedProtocol> {
+        Ok(RequestedTunnel {
+            protocol: RequestedProtocol::ReverseTunnel(ReverseTunnelConfigProtocol::try_from(local_protocol)?),
+            port,
+            destination_addr,
+            destination_host: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn any_match() -> Vec<MatchConfig> {
+        vec![MatchConfig::Any]
+    }
+
+    fn identity() -> AuthenticatedIdentity {
+        AuthenticatedIdentity::default()
+    }
+
+    fn tcp_tunnel(port: u16, destination_addr: IpAddr, destination_host: &str) -> RequestedTunnel {
+        RequestedTunnel {
+            protocol: RequestedProtocol::Tunnel(TunnelConfigProtocol::Tcp),
+            port,
+            destination_addr,
+            destination_host: destination_host.to_string(),
         }
     }
+
+    fn request<'a>(source_addr: IpAddr, tunnel: &'a RequestedTunnel, identity: &'a AuthenticatedIdentity) -> RestrictionRequest<'a> {
+        RestrictionRequest { path: "/", source_addr, sni: None, headers: &[], identity, tunnel }
+    }
+
+    #[test]
+    fn first_match_wins_deny_before_allow() {
+        let rules = RestrictionsRules {
+            restrictions: vec![
+                RestrictionConfig {
+                    name: "block-bad-actor".to_string(),
+                    r#match: vec![MatchConfig::SourceIp(vec!["10.0.0.0/8".parse().unwrap()])],
+                    allow: vec![],
+                    action: RestrictionAction::Deny,
+                },
+                RestrictionConfig { name: "allow-everyone-else".to_string(), r#match: any_match(), allow: vec![], action: RestrictionAction::Allow },
+            ],
+        };
+
+        let tunnel = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "example.com");
+        let id = identity();
+        assert_eq!(rules.evaluate(&request("10.1.2.3".parse().unwrap(), &tunnel, &id)), RestrictionAction::Deny);
+        assert_eq!(rules.evaluate(&request("192.168.1.1".parse().unwrap(), &tunnel, &id)), RestrictionAction::Allow);
+    }
+
+    #[test]
+    fn no_matching_rule_denies_by_default() {
+        let rules = RestrictionsRules { restrictions: vec![] };
+        let tunnel = tcp_tunnel(443, "10.1.2.3".parse().unwrap(), "example.com");
+        let id = identity();
+        assert_eq!(rules.evaluate(&request("127.0.0.1".parse().unwrap(), &tunnel, &id)), RestrictionAction::Deny);
+    }
+
+    #[test]
+    fn allow_clause_filters_on_port_and_host() {
+        let allow = AllowConfig::Tunnel(AllowTunnelConfig {
+            protocol: vec![TunnelConfigProtocol::Tcp],
+            port: vec![443..=443],
+            host: Regex::new(r"^.*\.corp$").unwrap(),
+            cidr: default_cidr(),
+            auth: None,
+            max_connections: None,
+            idle_timeout: None,
+        });
+        let rules = RestrictionsRules {
+            restrictions: vec![RestrictionConfig { name: "web".to_string(), r#match: any_match(), allow: vec![allow], action: RestrictionAction::Allow }],
+        };
+        let id = identity();
+
+        let source: IpAddr = "127.0.0.1".parse().unwrap();
+        let matching = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "api.corp");
+        assert_eq!(rules.evaluate(&request(source, &matching, &id)), RestrictionAction::Allow);
+
+        let wrong_port = tcp_tunnel(8080, "1.2.3.4".parse().unwrap(), "api.corp");
+        assert_eq!(rules.evaluate(&request(source, &wrong_port, &id)), RestrictionAction::Deny);
+
+        let wrong_host = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "api.example.com");
+        assert_eq!(rules.evaluate(&request(source, &wrong_host, &id)), RestrictionAction::Deny);
+    }
+
+    #[test]
+    fn requested_tunnel_rejects_unsupported_local_protocol() {
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(RequestedTunnel::for_tunnel(&LocalProtocol::Stdio, 22, addr, "host".to_string()).is_err());
+        assert!(RequestedTunnel::for_reverse_tunnel(&LocalProtocol::Stdio, 22, addr).is_err());
+
+        let tunnel = RequestedTunnel::for_tunnel(&LocalProtocol::Tcp { proxy_protocol: false }, 22, addr, "host".to_string()).unwrap();
+        assert!(matches!(tunnel.protocol, RequestedProtocol::Tunnel(TunnelConfigProtocol::Tcp)));
+
+        let reverse = RequestedTunnel::for_reverse_tunnel(&LocalProtocol::ReverseSocks5, 1080, addr).unwrap();
+        assert!(matches!(reverse.protocol, RequestedProtocol::ReverseTunnel(ReverseTunnelConfigProtocol::Socks5)));
+    }
+
+    #[test]
+    fn sni_match_requires_sni_present_and_matching() {
+        let m = MatchConfig::Sni(Regex::new(r"^(foo|bar)\.corp$").unwrap());
+        let tunnel = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "example.com");
+        let id = identity();
+
+        let with_matching_sni = RestrictionRequest { path: "/", source_addr: "127.0.0.1".parse().unwrap(), sni: Some("foo.corp"), headers: &[], identity: &id, tunnel: &tunnel };
+        assert!(m.matches(&with_matching_sni));
+
+        let with_other_sni = RestrictionRequest { path: "/", source_addr: "127.0.0.1".parse().unwrap(), sni: Some("evil.corp"), headers: &[], identity: &id, tunnel: &tunnel };
+        assert!(!m.matches(&with_other_sni));
+
+        let without_sni = RestrictionRequest { path: "/", source_addr: "127.0.0.1".parse().unwrap(), sni: None, headers: &[], identity: &id, tunnel: &tunnel };
+        assert!(!m.matches(&without_sni), "a rule requiring SNI must not match a request that presents none");
+    }
+
+    #[test]
+    fn http_header_match_is_case_insensitive_on_the_header_name() {
+        let m = MatchConfig::HttpHeader { name: "x-api-key".to_string(), value: Regex::new(r"^secret-.+$").unwrap() };
+        let tunnel = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "example.com");
+        let id = identity();
+        let source_addr: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let headers = [("X-Api-Key".to_string(), "secret-abc".to_string())];
+        let matching_case = RestrictionRequest { path: "/", source_addr, sni: None, headers: &headers, identity: &id, tunnel: &tunnel };
+        assert!(m.matches(&matching_case), "header name lookup must be case-insensitive");
+
+        let wrong_value = [("x-api-key".to_string(), "wrong".to_string())];
+        let non_matching_value = RestrictionRequest { path: "/", source_addr, sni: None, headers: &wrong_value, identity: &id, tunnel: &tunnel };
+        assert!(!m.matches(&non_matching_value));
+
+        let no_headers = RestrictionRequest { path: "/", source_addr, sni: None, headers: &[], identity: &id, tunnel: &tunnel };
+        assert!(!m.matches(&no_headers));
+    }
+
+    #[test]
+    fn evaluate_with_limits_surfaces_the_winning_clause_limits() {
+        let allow = AllowConfig::Tunnel(AllowTunnelConfig {
+            protocol: vec![],
+            port: vec![],
+            host: default_host(),
+            cidr: default_cidr(),
+            auth: None,
+            max_connections: Some(1),
+            idle_timeout: None,
+        });
+        let rules = RestrictionsRules {
+            restrictions: vec![RestrictionConfig { name: "web".to_string(), r#match: any_match(), allow: vec![allow], action: RestrictionAction::Allow }],
+        };
+        let tunnel = tcp_tunnel(443, "1.2.3.4".parse().unwrap(), "example.com");
+        let id = identity();
+
+        let (action, limits) = rules.evaluate_with_limits(&request("127.0.0.1".parse().unwrap(), &tunnel, &id));
+        assert_eq!(action, RestrictionAction::Allow);
+        let (name, limits) = limits.expect("an allow clause admitted the request");
+        assert_eq!(name, "web");
+        assert_eq!(limits.max_connections, Some(1));
+    }
+
+    #[test]
+    fn compact_allow_syntax_parses_lowercase_protocol() {
+        let allow = AllowConfig::from_str("tunnel:protocol=tcp;port=443").expect("valid compact allow clause");
+        let AllowConfig::Tunnel(cfg) = allow else { panic!("expected a Tunnel clause") };
+        assert_eq!(cfg.protocol, vec![TunnelConfigProtocol::Tcp]);
+        assert_eq!(cfg.port, vec![443..=443]);
+
+        let allow = AllowConfig::from_str("reverse-tunnel:protocol=udp").expect("valid compact allow clause");
+        let AllowConfig::ReverseTunnel(cfg) = allow else { panic!("expected a ReverseTunnel clause") };
+        assert_eq!(cfg.protocol, vec![ReverseTunnelConfigProtocol::Udp]);
+    }
+
+    #[test]
+    fn protocol_deserializes_legacy_pascal_case_alongside_lowercase() {
+        let protocol: TunnelConfigProtocol = serde_yaml::from_str("Tcp").expect("legacy PascalCase still parses");
+        assert_eq!(protocol, TunnelConfigProtocol::Tcp);
+        let protocol: TunnelConfigProtocol = serde_yaml::from_str("tcp").expect("new lowercase form parses");
+        assert_eq!(protocol, TunnelConfigProtocol::Tcp);
+
+        let protocol: ReverseTunnelConfigProtocol = serde_yaml::from_str("Socks5").expect("legacy PascalCase still parses");
+        assert_eq!(protocol, ReverseTunnelConfigProtocol::Socks5);
+        let protocol: ReverseTunnelConfigProtocol = serde_yaml::from_str("socks5").expect("new lowercase form parses");
+        assert_eq!(protocol, ReverseTunnelConfigProtocol::Socks5);
+    }
+
+    #[test]
+    fn compact_syntax_escaped_comma_is_not_mistaken_for_a_field_boundary() {
+        // The `http-header` regex below contains a literal `,action=` that would otherwise
+        // look like the start of the `action` field; the `\,` keeps it part of `match`.
+        let config = RestrictionConfig::from_str(r"name=web,match=http-header:x-env=.*\,action=prod,action=allow")
+            .expect("escaped comma should not split the match field");
+        assert_eq!(config.name, "web");
+        assert_eq!(config.action, RestrictionAction::Allow);
+        assert_eq!(config.r#match.len(), 1);
+        let MatchConfig::HttpHeader { name, value } = &config.r#match[0] else { panic!("expected an HttpHeader match") };
+        assert_eq!(name, "x-env");
+        assert!(value.is_match("staging,action=prod"));
+        assert!(!value.is_match("staging"));
+    }
+
+    #[test]
+    fn compact_syntax_escaped_pipe_is_not_mistaken_for_a_match_entry_boundary() {
+        // `|` also separates `match=`/`allow=` entries, so an alternation regex must escape
+        // its own `|` as `\|` to stay part of a single entry instead of being split in two.
+        let config = RestrictionConfig::from_str(r"name=web,match=sni:(foo\|bar)\.corp$,action=allow")
+            .expect("escaped pipe should not split the match field");
+        assert_eq!(config.r#match.len(), 1);
+        let MatchConfig::Sni(regex) = &config.r#match[0] else { panic!("expected an Sni match") };
+        assert!(regex.is_match("foo.corp"));
+        assert!(regex.is_match("bar.corp"));
+        assert!(!regex.is_match("baz.corp"));
+    }
+
+    #[test]
+    fn masked_string_never_prints_its_contents() {
+        let secret = MaskedString("super-secret-token".to_string());
+        assert_eq!(format!("{secret:?}"), "MASKED");
+        assert_eq!(format!("{secret}"), "MASKED");
+    }
+
+    #[test]
+    fn masked_string_constant_time_eq_matches_equality() {
+        let token = MaskedString("correct-horse".to_string());
+        assert!(token.constant_time_eq(&MaskedString("correct-horse".to_string())));
+        assert!(!token.constant_time_eq(&MaskedString("correct-house".to_string())));
+        assert!(!token.constant_time_eq(&MaskedString("correct-horse-but-longer".to_string())));
+    }
+
+    #[test]
+    fn bearer_token_auth_admits_matching_token_and_rejects_others() {
+        let auth = AllowAuthConfig::BearerToken(vec![MaskedString("s3cr3t".to_string())]);
+
+        let matching = AuthenticatedIdentity { bearer_token: Some(MaskedString("s3cr3t".to_string())), client_cert_cn: None };
+        assert!(auth.is_satisfied_by(&matching));
+
+        let wrong_token = AuthenticatedIdentity { bearer_token: Some(MaskedString("wrong".to_string())), client_cert_cn: None };
+        assert!(!auth.is_satisfied_by(&wrong_token));
+
+        let no_token = AuthenticatedIdentity::default();
+        assert!(!auth.is_satisfied_by(&no_token));
+    }
+
+    #[test]
+    fn client_cert_cn_auth_admits_matching_regex_and_rejects_others() {
+        let auth = AllowAuthConfig::ClientCertCn(Regex::new(r"^.*\.corp$").unwrap());
+
+        let matching = AuthenticatedIdentity { bearer_token: None, client_cert_cn: Some("client.corp".to_string()) };
+        assert!(auth.is_satisfied_by(&matching));
+
+        let wrong_cn = AuthenticatedIdentity { bearer_token: None, client_cert_cn: Some("client.example.com".to_string()) };
+        assert!(!auth.is_satisfied_by(&wrong_cn));
+
+        let no_cert = AuthenticatedIdentity::default();
+        assert!(!auth.is_satisfied_by(&no_cert));
+    }
 }
@@ -0,0 +1,245 @@
+use crate::restrictions::types::{ConnectionLimits, RestrictionAction, RestrictionRequest, RestrictionsRules};
+use arc_swap::ArcSwap;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Shared handle to the currently active restriction rules. Cloning is cheap; every clone
+/// observes the latest ruleset accepted by the watcher.
+#[derive(Clone)]
+pub struct RestrictionsRulesHandle(Arc<ArcSwap<RestrictionsRules>>);
+
+impl RestrictionsRulesHandle {
+    pub fn new(rules: RestrictionsRules) -> Self {
+        Self(Arc::new(ArcSwap::new(Arc::new(rules))))
+    }
+
+    /// Returns the ruleset that was current at the time of the call. The hot path should
+    /// call this once per request rather than caching the result, so it always sees the
+    /// latest accepted reload.
+    pub fn load(&self) -> Arc<RestrictionsRules> {
+        self.0.load_full()
+    }
+
+    /// Evaluates `request` against whichever ruleset is current at the time of the call, so
+    /// callers on the hot path automatically pick up reloads without caching a stale `Arc`.
+    pub fn evaluate(&self, request: &RestrictionRequest) -> RestrictionAction {
+        self.load().evaluate(request)
+    }
+
+    /// Like `evaluate`, but also returns the winning rule's name and `ConnectionLimits`, for
+    /// callers that admit a tunnel and need to enforce `max_connections`/`idle_timeout` via
+    /// the session accounting layer.
+    pub fn evaluate_with_limits(&self, request: &RestrictionRequest) -> (RestrictionAction, Option<(String, ConnectionLimits)>) {
+        self.load().evaluate_with_limits(request)
+    }
+
+    fn swap(&self, rules: RestrictionsRules) -> Arc<RestrictionsRules> {
+        self.0.swap(Arc::new(rules))
+    }
+}
+
+fn load_restrictions_file(path: &Path) -> anyhow::Result<RestrictionsRules> {
+    let content = std::fs::read_to_string(path)?;
+    let rules: RestrictionsRules = serde_yaml::from_str(&content)?;
+    Ok(rules)
+}
+
+fn rule_names(rules: &RestrictionsRules) -> HashSet<&str> {
+    rules.restrictions.iter().map(|r| r.name.as_str()).collect()
+}
+
+fn log_rule_diff(previous: &RestrictionsRules, new: &RestrictionsRules) {
+    let previous_names = rule_names(previous);
+    let new_names = rule_names(new);
+
+    let added: Vec<&str> = new_names.difference(&previous_names).copied().collect();
+    let removed: Vec<&str> = previous_names.difference(&new_names).copied().collect();
+
+    if !added.is_empty() || !removed.is_empty() {
+        tracing::info!("Restrictions reloaded: rules added={added:?} removed={removed:?}");
+    } else {
+        tracing::info!("Restrictions reloaded, no rules added or removed");
+    }
+}
+
+/// Watches `path` for changes and atomically swaps the rules held by `handle` whenever the
+/// file changes and still parses. If the new file fails to load, the previous ruleset is
+/// kept and the error is logged, so a typo in the config can never drop all running tunnels.
+///
+/// The watch is placed on the *parent directory* rather than the file itself: on inotify (and
+/// most backends `notify` wraps) a watch on a file path tracks the inode, so an atomic
+/// replace (editors and config-management tools write to a temp file then rename it over the
+/// target, which is exactly the case this module's debounce sleep exists for) would silently
+/// stop the watch from firing on any further edit to the new file at that path. Watching the
+/// directory and filtering events down to `path` survives the rename.
+pub fn spawn_restrictions_watcher(path: PathBuf, handle: RestrictionsRulesHandle) -> anyhow::Result<RecommendedWatcher> {
+    let watch_dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&watch_dir, RecursiveMode::NonRecursive)?;
+
+    // `notify` reports event paths as absolute, so compare against the absolute form of
+    // `path`; a relative path would otherwise never match any event.
+    let watch_path = if path.is_absolute() { path.clone() } else { std::env::current_dir()?.join(&path) };
+    std::thread::spawn(move || {
+        for event in rx {
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    tracing::warn!("Error watching restrictions file {watch_path:?}: {err}");
+                    continue;
+                }
+            };
+
+            if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+                continue;
+            }
+
+            if !event.paths.contains(&watch_path) {
+                continue;
+            }
+
+            // Editors often replace the file (write to a temp file + rename), which fires
+            // several events in quick succession. Give the write a moment to settle before
+            // reading it back.
+            std::thread::sleep(Duration::from_millis(100));
+
+            match load_restrictions_file(&watch_path) {
+                Ok(new_rules) => {
+                    let previous = handle.swap(new_rules.clone());
+                    log_rule_diff(&previous, &new_rules);
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to reload restrictions file {watch_path:?}, keeping previous rules: {err}");
+                }
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{AuthenticatedIdentity, RequestedProtocol, RequestedTunnel, TunnelConfigProtocol};
+    use std::net::IpAddr;
+
+    const ALLOW_ALL_YAML: &str = "restrictions:\n  - name: allow-all\n    match:\n      - Any\n    allow: []\n    action: Allow\n";
+    const DENY_ALL_YAML: &str = "restrictions:\n  - name: deny-all\n    match:\n      - Any\n    allow: []\n    action: Deny\n";
+    const ALLOW_WITH_LIMITS_YAML: &str =
+        "restrictions:\n  - name: limited\n    match:\n      - Any\n    allow:\n      - Tunnel:\n          max_connections: 3\n    action: Allow\n";
+
+    fn tcp_tunnel() -> RequestedTunnel {
+        RequestedTunnel {
+            protocol: RequestedProtocol::Tunnel(TunnelConfigProtocol::Tcp),
+            port: 443,
+            destination_addr: "1.2.3.4".parse().unwrap(),
+            destination_host: "example.com".to_string(),
+        }
+    }
+
+    fn request<'a>(tunnel: &'a RequestedTunnel, identity: &'a AuthenticatedIdentity) -> RestrictionRequest<'a> {
+        let source_addr: IpAddr = "127.0.0.1".parse().unwrap();
+        RestrictionRequest { path: "/", source_addr, sni: None, headers: &[], identity, tunnel }
+    }
+
+    #[test]
+    fn handle_evaluate_reflects_whatever_is_currently_loaded() {
+        let rules = load_restrictions_file_from_str(ALLOW_ALL_YAML);
+        let handle = RestrictionsRulesHandle::new(rules);
+        let tunnel = tcp_tunnel();
+        let identity = AuthenticatedIdentity::default();
+        assert_eq!(handle.evaluate(&request(&tunnel, &identity)), RestrictionAction::Allow);
+
+        handle.swap(load_restrictions_file_from_str(DENY_ALL_YAML));
+        assert_eq!(handle.evaluate(&request(&tunnel, &identity)), RestrictionAction::Deny);
+    }
+
+    #[test]
+    fn handle_evaluate_with_limits_reflects_whatever_is_currently_loaded() {
+        let rules = load_restrictions_file_from_str(ALLOW_WITH_LIMITS_YAML);
+        let handle = RestrictionsRulesHandle::new(rules);
+        let tunnel = tcp_tunnel();
+        let identity = AuthenticatedIdentity::default();
+
+        let (action, limits) = handle.evaluate_with_limits(&request(&tunnel, &identity));
+        assert_eq!(action, RestrictionAction::Allow);
+        let (name, limits) = limits.expect("the allow clause should size a limit");
+        assert_eq!(name, "limited");
+        assert_eq!(limits.max_connections, Some(3));
+    }
+
+    #[test]
+    fn spawn_restrictions_watcher_reloads_on_file_change() {
+        let path = std::env::temp_dir().join(format!("wstunnel-restrictions-test-{:?}.yaml", std::thread::current().id()));
+        std::fs::write(&path, ALLOW_ALL_YAML).unwrap();
+
+        let handle = RestrictionsRulesHandle::new(load_restrictions_file(&path).unwrap());
+        let _watcher = spawn_restrictions_watcher(path.clone(), handle.clone()).unwrap();
+
+        let tunnel = tcp_tunnel();
+        let identity = AuthenticatedIdentity::default();
+        assert_eq!(handle.evaluate(&request(&tunnel, &identity)), RestrictionAction::Allow);
+
+        std::fs::write(&path, DENY_ALL_YAML).unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..50 {
+            std::thread::sleep(Duration::from_millis(100));
+            if handle.evaluate(&request(&tunnel, &identity)) == RestrictionAction::Deny {
+                reloaded = true;
+                break;
+            }
+        }
+
+        std::fs::remove_file(&path).ok();
+        assert!(reloaded, "watcher did not pick up the updated restrictions file in time");
+    }
+
+    #[test]
+    fn spawn_restrictions_watcher_reloads_after_atomic_replace() {
+        // Simulates the editor/config-management write pattern this module's debounce sleep
+        // is meant to handle: write the new content to a sibling temp file, then rename it
+        // over the watched path. A watch placed on the file itself (rather than its parent
+        // directory) would track the old inode and silently stop firing here.
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("wstunnel-restrictions-atomic-test-{:?}.yaml", std::thread::current().id()));
+        let tmp_path = dir.join(format!("wstunnel-restrictions-atomic-test-{:?}.yaml.tmp", std::thread::current().id()));
+        std::fs::write(&path, ALLOW_ALL_YAML).unwrap();
+
+        let handle = RestrictionsRulesHandle::new(load_restrictions_file(&path).unwrap());
+        let _watcher = spawn_restrictions_watcher(path.clone(), handle.clone()).unwrap();
+
+        let tunnel = tcp_tunnel();
+        let identity = AuthenticatedIdentity::default();
+        assert_eq!(handle.evaluate(&request(&tunnel, &identity)), RestrictionAction::Allow);
+
+        std::fs::write(&tmp_path, DENY_ALL_YAML).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..50 {
+            std::thread::sleep(Duration::from_millis(100));
+            if handle.evaluate(&request(&tunnel, &identity)) == RestrictionAction::Deny {
+                reloaded = true;
+                break;
+            }
+        }
+
+        std::fs::remove_file(&path).ok();
+        assert!(reloaded, "watcher did not pick up a reload delivered via atomic rename");
+    }
+
+    fn load_restrictions_file_from_str(yaml: &str) -> RestrictionsRules {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+}